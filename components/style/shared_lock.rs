@@ -0,0 +1,155 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A lock that can be shared by many objects, and the objects it guards.
+//!
+//! `stylesheets::CssRule` and friends use this to guard the individual
+//! rules of a stylesheet with a single lock: every rule in the same author
+//! stylesheet is wrapped in a `Locked<T>` that shares one `SharedRwLock`,
+//! so a single read or write guard is enough to read or mutate any number
+//! of them.
+
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use servo_arc::Arc;
+use std::cell::UnsafeCell;
+use std::fmt;
+
+/// A shared read/write lock that can protect multiple objects, such as the
+/// rules inside of a stylesheet.
+///
+/// Cloning a `SharedRwLock` gives you another handle to the *same*
+/// underlying lock (it's reference-counted), not an independent one.
+#[derive(Clone)]
+pub struct SharedRwLock {
+    arc: Arc<RwLock<()>>,
+}
+
+impl fmt::Debug for SharedRwLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SharedRwLock")
+    }
+}
+
+impl SharedRwLock {
+    /// Creates a new, unique shared lock.
+    pub fn new() -> Self {
+        SharedRwLock { arc: Arc::new(RwLock::new(())) }
+    }
+
+    /// Wraps `data` so that it's protected by this lock.
+    pub fn wrap<T>(&self, data: T) -> Locked<T> {
+        Locked {
+            shared_lock: self.clone(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// Acquires this lock for reading, returning a guard that proves as
+    /// much to any `Locked<T>` sharing this lock.
+    pub fn read(&self) -> SharedRwLockReadGuard {
+        SharedRwLockReadGuard { lock: self, _guard: self.arc.read() }
+    }
+
+    /// Acquires this lock for writing, returning a guard that proves as
+    /// much to any `Locked<T>` sharing this lock.
+    pub fn write(&self) -> SharedRwLockWriteGuard {
+        SharedRwLockWriteGuard { lock: self, _guard: self.arc.write() }
+    }
+
+    fn ptr(&self) -> *const RwLock<()> {
+        &*self.arc
+    }
+}
+
+/// A proof that some `SharedRwLock` is currently held for reading.
+pub struct SharedRwLockReadGuard<'a> {
+    lock: &'a SharedRwLock,
+    _guard: RwLockReadGuard<'a, ()>,
+}
+
+/// A proof that some `SharedRwLock` is currently held for writing.
+pub struct SharedRwLockWriteGuard<'a> {
+    lock: &'a SharedRwLock,
+    _guard: RwLockWriteGuard<'a, ()>,
+}
+
+/// A value protected by a `SharedRwLock`. Reading or writing it requires a
+/// guard obtained from that same lock; mismatched guards are rejected.
+pub struct Locked<T> {
+    shared_lock: SharedRwLock,
+    data: UnsafeCell<T>,
+}
+
+// A `Locked<T>` is only ever read through a `SharedRwLockReadGuard`, and
+// written through a `SharedRwLockWriteGuard`, both of which prove the
+// underlying lock is held; that's what makes sharing and sending it
+// across threads sound regardless of `T`'s own thread-safety.
+unsafe impl<T> Sync for Locked<T> {}
+unsafe impl<T> Send for Locked<T> {}
+
+impl<T> fmt::Debug for Locked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Locked(..)")
+    }
+}
+
+impl<T> Locked<T> {
+    /// Returns a reference to the underlying value, given proof (`guard`)
+    /// that the lock protecting it is currently held.
+    pub fn read_with<'a>(&'a self, guard: &'a SharedRwLockReadGuard<'a>) -> &'a T {
+        assert_eq!(
+            self.shared_lock.ptr(), guard.lock.ptr(),
+            "Tried to read a Locked<T> with a guard from an unrelated SharedRwLock"
+        );
+        unsafe { &*self.data.get() }
+    }
+
+    /// Returns a mutable reference to the underlying value, given proof
+    /// (`guard`) that the lock protecting it is currently held for
+    /// writing.
+    pub fn write_with<'a>(&'a self, guard: &'a mut SharedRwLockWriteGuard) -> &'a mut T {
+        assert_eq!(
+            self.shared_lock.ptr(), guard.lock.ptr(),
+            "Tried to write a Locked<T> with a guard from an unrelated SharedRwLock"
+        );
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+/// Parameters that customize how `DeepCloneWithLock::deep_clone_with_lock`
+/// behaves.
+#[derive(Clone, Debug, Default)]
+pub struct DeepCloneParams {
+    /// Whether rules that haven't been mutated since they were parsed may
+    /// be shared (copy-on-write) with the clone instead of eagerly
+    /// recreating their contents. See
+    /// `stylesheets::LockedOrImmutable::share`, which is what actually
+    /// implements the sharing when this is set.
+    ///
+    /// Defaults to `false`: callers that need a rule to be genuinely
+    /// independent right away (e.g. inserting a rule parsed from
+    /// un-trusted CSSOM text) should leave this unset.
+    pub use_cow: bool,
+}
+
+/// A trait for deep-cloning a value that's guarded by a `SharedRwLock`,
+/// given a destination lock to re-wrap any nested locked data with.
+pub trait DeepCloneWithLock {
+    /// Performs the deep clone.
+    fn deep_clone_with_lock(
+        &self,
+        lock: &SharedRwLock,
+        guard: &SharedRwLockReadGuard,
+        params: &DeepCloneParams,
+    ) -> Self;
+}
+
+/// A trait for serializing a value as CSS text, given a guard proving the
+/// lock protecting it (and anything it refers to) is held.
+pub trait ToCssWithGuard {
+    /// Serializes `self` to `dest`.
+    fn to_css<W>(&self, guard: &SharedRwLockReadGuard, dest: &mut W) -> fmt::Result
+    where
+        W: fmt::Write;
+}
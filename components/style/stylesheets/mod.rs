@@ -30,6 +30,7 @@ use parser::{ParserContext, ParserErrorContext};
 use servo_arc::Arc;
 use shared_lock::{DeepCloneParams, DeepCloneWithLock, Locked, SharedRwLock, SharedRwLockReadGuard, ToCssWithGuard};
 use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
 use style_traits::PARSING_MODE_DEFAULT;
 
 pub use self::counter_style_rule::CounterStyleRule;
@@ -56,6 +57,48 @@ pub use self::style_rule::StyleRule;
 pub use self::supports_rule::SupportsRule;
 pub use self::viewport_rule::ViewportRule;
 
+lazy_static! {
+    /// The lock shared by every UA and user stylesheet's rules.
+    ///
+    /// See `LockedOrImmutable` for why UA/user stylesheets can all share one
+    /// lock (or skip locking entirely) while author stylesheets can't.
+    static ref UA_OR_USER_RULES_LOCK: SharedRwLock = SharedRwLock::new();
+}
+
+/// Returns the lock that should guard rules belonging to a stylesheet with
+/// the given `origin`.
+///
+/// Author rules use `document_lock`, the lock owned by the document the
+/// stylesheet lives in. UA and user rules use the process-wide
+/// `UA_OR_USER_RULES_LOCK` instead (see `LockedOrImmutable` for why that's
+/// sound).
+///
+/// This alone only affects the lock used while parsing: it's `CssRule`'s
+/// representation (see `LockedOrImmutable`) that actually makes UA/user
+/// rules lock-free to *read* afterwards, via `to_css`, matching, etc. A
+/// `Locked<T>::read_with` call against a guard from the wrong
+/// `SharedRwLock` asserts rather than silently returning the wrong data,
+/// so using this function in isolation, without also routing UA/user
+/// rules through `LockedOrImmutable::Immutable`, would be loudly unsafe
+/// (a panic on first mismatched read) rather than silently so.
+///
+/// `StylesheetContents::from_str` and `StylesheetContents::clone_with_lock`
+/// are the only callers: each resolves the lock once, here, and stores the
+/// result as
+/// `StylesheetContents::shared_lock` rather than re-deriving it — nested
+/// rule lists (`@media`, `@supports`, `@document`) inherit that same
+/// resolved lock by construction (`rule_parser::parse_nested_rules`
+/// reuses `self.shared_lock`), and every later reader goes through
+/// `StylesheetContents::shared_lock` too, so there's only ever one lock
+/// in play for a given stylesheet's rules, not a mix of this function's
+/// result and the document's raw lock.
+fn rule_lock_for_origin(origin: Origin, document_lock: &SharedRwLock) -> &SharedRwLock {
+    match origin {
+        Origin::Author => document_lock,
+        Origin::UserAgent | Origin::User => &UA_OR_USER_RULES_LOCK,
+    }
+}
+
 /// Extra data that the backend may need to resolve url values.
 #[cfg(not(feature = "gecko"))]
 pub type UrlExtraData = ::servo_url::ServoUrl;
@@ -86,6 +129,114 @@ impl UrlExtraData {
 #[cfg(feature = "gecko")]
 impl Eq for UrlExtraData {}
 
+/// A rule's contents, guarded by a `SharedRwLock` if the rule may still be
+/// mutated through CSSOM, or held as a bare `Arc` if it can't be.
+///
+/// UA and user stylesheets are parsed once and never mutated afterwards, so
+/// reading their rules during selector matching doesn't need to acquire a
+/// lock at all; author stylesheets, which CSSOM can mutate at any time,
+/// keep going through a `Locked<T>` as before. A `Locked` rule also carries
+/// a "shared" bit: a rule produced by `share()` (a copy-on-write deep
+/// clone) sets it on both copies, so that whichever copy is mutated first
+/// through the CSSOM knows it must diverge into its own independent copy
+/// rather than mutating in place.
+#[allow(missing_docs)]
+pub enum LockedOrImmutable<T> {
+    Locked(Arc<Locked<T>>, Arc<AtomicBool>),
+    Immutable(Arc<T>),
+}
+
+// Written by hand rather than derived: the only data actually being
+// cloned is an `Arc`, so there's no need to require `T: Clone`, which a
+// `#[derive(Clone)]` would add even though most of the rule types stored
+// here (`StyleRule`, `MediaRule`, etc.) aren't `Clone` at all — they only
+// support `DeepCloneWithLock`.
+impl<T> Clone for LockedOrImmutable<T> {
+    fn clone(&self) -> Self {
+        match *self {
+            LockedOrImmutable::Locked(ref arc, ref shared) => {
+                LockedOrImmutable::Locked(arc.clone(), shared.clone())
+            },
+            LockedOrImmutable::Immutable(ref arc) => LockedOrImmutable::Immutable(arc.clone()),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for LockedOrImmutable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LockedOrImmutable::Locked(..) => f.write_str("LockedOrImmutable::Locked(..)"),
+            LockedOrImmutable::Immutable(..) => f.write_str("LockedOrImmutable::Immutable(..)"),
+        }
+    }
+}
+
+impl<T> LockedOrImmutable<T> {
+    /// Wraps `value` the right way for a stylesheet with the given
+    /// `origin`: author values are locked behind `lock`, UA/user values are
+    /// stored as a bare, lock-free `Arc`.
+    fn new(value: T, origin: Origin, lock: &SharedRwLock) -> Self {
+        match origin {
+            Origin::Author => LockedOrImmutable::Locked(
+                Arc::new(lock.wrap(value)), Arc::new(AtomicBool::new(false))),
+            Origin::UserAgent | Origin::User => LockedOrImmutable::Immutable(Arc::new(value)),
+        }
+    }
+
+    /// Returns a reference to the underlying value. `guard` is only
+    /// actually taken if this rule is `Locked`.
+    fn read<'a>(&'a self, guard: &'a SharedRwLockReadGuard) -> &'a T {
+        match *self {
+            LockedOrImmutable::Locked(ref arc, _) => arc.read_with(guard),
+            LockedOrImmutable::Immutable(ref arc) => &*arc,
+        }
+    }
+
+    /// Returns a copy-on-write clone: rather than recreating `T`, this
+    /// just bumps the refcount of the existing `Arc` and (for `Locked`
+    /// rules) marks it shared, deferring the real clone to `make_unique`,
+    /// the next time a CSSOM mutation actually reaches one of the copies.
+    fn share(&self) -> Self {
+        match *self {
+            LockedOrImmutable::Locked(ref arc, ref shared) => {
+                shared.store(true, Ordering::Relaxed);
+                LockedOrImmutable::Locked(arc.clone(), shared.clone())
+            },
+            LockedOrImmutable::Immutable(ref arc) => LockedOrImmutable::Immutable(arc.clone()),
+        }
+    }
+
+    /// Wraps `value` the same way `self` is currently wrapped: if `self`
+    /// is `Locked`, so is the result (behind `lock`); if `self` is
+    /// `Immutable`, so is the result and `lock` is ignored.
+    ///
+    /// Used by `deep_clone_with_lock` so that cloning a rule preserves its
+    /// origin's representation instead of assuming every clone becomes an
+    /// author (`Locked`) rule — which would wrongly make a UA/user rule
+    /// mutable through CSSOM after a deep clone.
+    fn clone_wrapped_like(&self, value: T, lock: &SharedRwLock) -> Self {
+        match *self {
+            LockedOrImmutable::Locked(..) => LockedOrImmutable::Locked(
+                Arc::new(lock.wrap(value)), Arc::new(AtomicBool::new(false))),
+            LockedOrImmutable::Immutable(_) => LockedOrImmutable::Immutable(Arc::new(value)),
+        }
+    }
+
+    /// Returns whether this rule was produced by `share()` and hasn't
+    /// diverged into its own independent copy yet. Always `false` for
+    /// `Immutable` rules, which are never mutated in the first place.
+    ///
+    /// This is the actual read of the "shared" bit: `share()` only ever
+    /// sets it, and it's this check, run right before a CSSOM mutation,
+    /// that gives it meaning.
+    fn is_shared(&self) -> bool {
+        match *self {
+            LockedOrImmutable::Locked(_, ref shared) => shared.load(Ordering::Relaxed),
+            LockedOrImmutable::Immutable(_) => false,
+        }
+    }
+}
+
 /// A CSS rule.
 ///
 /// TODO(emilio): Lots of spec links should be around.
@@ -95,18 +246,18 @@ pub enum CssRule {
     // No Charset here, CSSCharsetRule has been removed from CSSOM
     // https://drafts.csswg.org/cssom/#changes-from-5-december-2013
 
-    Namespace(Arc<Locked<NamespaceRule>>),
-    Import(Arc<Locked<ImportRule>>),
-    Style(Arc<Locked<StyleRule>>),
-    Media(Arc<Locked<MediaRule>>),
-    FontFace(Arc<Locked<FontFaceRule>>),
-    FontFeatureValues(Arc<Locked<FontFeatureValuesRule>>),
-    CounterStyle(Arc<Locked<CounterStyleRule>>),
-    Viewport(Arc<Locked<ViewportRule>>),
-    Keyframes(Arc<Locked<KeyframesRule>>),
-    Supports(Arc<Locked<SupportsRule>>),
-    Page(Arc<Locked<PageRule>>),
-    Document(Arc<Locked<DocumentRule>>),
+    Namespace(LockedOrImmutable<NamespaceRule>),
+    Import(LockedOrImmutable<ImportRule>),
+    Style(LockedOrImmutable<StyleRule>),
+    Media(LockedOrImmutable<MediaRule>),
+    FontFace(LockedOrImmutable<FontFaceRule>),
+    FontFeatureValues(LockedOrImmutable<FontFeatureValuesRule>),
+    CounterStyle(LockedOrImmutable<CounterStyleRule>),
+    Viewport(LockedOrImmutable<ViewportRule>),
+    Keyframes(LockedOrImmutable<KeyframesRule>),
+    Supports(LockedOrImmutable<SupportsRule>),
+    Page(LockedOrImmutable<PageRule>),
+    Document(LockedOrImmutable<DocumentRule>),
 }
 
 impl MallocSizeOfWithGuard for CssRule {
@@ -126,11 +277,11 @@ impl MallocSizeOfWithGuard for CssRule {
             CssRule::Import(_) => 0,
 
             CssRule::Style(ref lock) => {
-                lock.read_with(guard).malloc_size_of_children(guard, malloc_size_of)
+                lock.read(guard).malloc_size_of_children(guard, malloc_size_of)
             },
 
             CssRule::Media(ref lock) => {
-                lock.read_with(guard).malloc_size_of_children(guard, malloc_size_of)
+                lock.read(guard).malloc_size_of_children(guard, malloc_size_of)
             },
 
             CssRule::FontFace(_) => 0,
@@ -140,15 +291,15 @@ impl MallocSizeOfWithGuard for CssRule {
             CssRule::Keyframes(_) => 0,
 
             CssRule::Supports(ref lock) => {
-                lock.read_with(guard).malloc_size_of_children(guard, malloc_size_of)
+                lock.read(guard).malloc_size_of_children(guard, malloc_size_of)
             },
 
             CssRule::Page(ref lock) => {
-                lock.read_with(guard).malloc_size_of_children(guard, malloc_size_of)
+                lock.read(guard).malloc_size_of_children(guard, malloc_size_of)
             },
 
             CssRule::Document(ref lock) => {
-                lock.read_with(guard).malloc_size_of_children(guard, malloc_size_of)
+                lock.read(guard).malloc_size_of_children(guard, malloc_size_of)
             },
         }
     }
@@ -189,6 +340,7 @@ pub enum SingleRuleParseError {
 }
 
 #[allow(missing_docs)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum RulesMutateError {
     Syntax,
     IndexSize,
@@ -238,10 +390,16 @@ impl CssRule {
     /// Returns a parsed CSS rule and the final state of the parser.
     ///
     /// Input state is None for a nested rule
+    ///
+    /// Unlike `StylesheetContents::from_str`, this doesn't take a separate
+    /// lock parameter: it always uses `parent_stylesheet_contents.shared_lock`,
+    /// the one `rule_lock_for_origin` already resolved for that stylesheet,
+    /// so a rule parsed here (e.g. by `parse_insert_rule`, for CSSOM's
+    /// `insertRule`) ends up guarded by the exact same lock as its
+    /// siblings — never a second, independently-obtained one.
     pub fn parse(
         css: &str,
         parent_stylesheet_contents: &StylesheetContents,
-        shared_lock: &SharedRwLock,
         state: Option<State>,
         loader: Option<&StylesheetLoader>
     ) -> Result<(Self, State), SingleRuleParseError> {
@@ -266,7 +424,7 @@ impl CssRule {
             stylesheet_origin: parent_stylesheet_contents.origin,
             context: context,
             error_context: ParserErrorContext { error_reporter: &error_reporter },
-            shared_lock: &shared_lock,
+            shared_lock: &parent_stylesheet_contents.shared_lock,
             loader: loader,
             state: state,
             had_hierarchy_error: false,
@@ -285,93 +443,427 @@ impl CssRule {
     }
 }
 
+/// Returns whether a stylesheet with the given `origin` is frozen against
+/// CSSOM mutation (see `LockedOrImmutable`), meaning any attempt to insert
+/// into or remove from its rule list must be rejected.
+fn is_frozen(origin: Origin) -> bool {
+    match origin {
+        Origin::Author => false,
+        Origin::UserAgent | Origin::User => true,
+    }
+}
+
+/// The hierarchy checks of the "insert a CSS rule" algorithm, expressed
+/// purely in terms of rule *types*:
+///
+/// https://drafts.csswg.org/cssom/#insert-a-css-rule
+///
+/// Factored out from `check_insert_rule_index` so it can be unit-tested
+/// without having to construct real `CssRule` values.
+fn check_insert_index(
+    existing_rule_types: &[CssRuleType],
+    index: usize,
+    new_rule_type: CssRuleType,
+) -> Result<(), RulesMutateError> {
+    if index > existing_rule_types.len() {
+        return Err(RulesMutateError::IndexSize);
+    }
+
+    match new_rule_type {
+        // @import rules must come before any rule other than another
+        // @import (Servo doesn't represent @charset, which would otherwise
+        // also be allowed before it).
+        CssRuleType::Import => {
+            if existing_rule_types[..index].iter().any(|t| *t != CssRuleType::Import) {
+                return Err(RulesMutateError::HierarchyRequest);
+            }
+        },
+        // @namespace rules must come after all @import rules, and before
+        // any rule that isn't an @import or another @namespace.
+        CssRuleType::Namespace => {
+            let ok = existing_rule_types[..index].iter().all(|t| {
+                *t == CssRuleType::Import || *t == CssRuleType::Namespace
+            }) && existing_rule_types[index..].iter().all(|t| *t != CssRuleType::Import);
+            if !ok {
+                return Err(RulesMutateError::HierarchyRequest);
+            }
+        },
+        // Every other rule must come after all @import and @namespace
+        // rules.
+        _ => {
+            let ok = existing_rule_types[index..].iter().all(|t| {
+                *t != CssRuleType::Import && *t != CssRuleType::Namespace
+            });
+            if !ok {
+                return Err(RulesMutateError::HierarchyRequest);
+            }
+        },
+    }
+
+    Ok(())
+}
+
+/// The check of the "remove a CSS rule" algorithm that depends on rule
+/// ordering, expressed purely in terms of rule *types*:
+///
+/// https://drafts.csswg.org/cssom/#remove-a-css-rule
+///
+/// Factored out from `check_remove_rule_index` so it can be unit-tested
+/// without having to construct real `CssRule` values.
+fn check_remove_index(
+    existing_rule_types: &[CssRuleType],
+    index: usize,
+) -> Result<(), RulesMutateError> {
+    let rule_type = match existing_rule_types.get(index) {
+        Some(rule_type) => *rule_type,
+        None => return Err(RulesMutateError::IndexSize),
+    };
+
+    // Removing a @namespace rule is only allowed if no rule other than an
+    // @import or another @namespace rule is present in the list.
+    if rule_type == CssRuleType::Namespace {
+        let ok = existing_rule_types.iter().all(|t| {
+            *t == CssRuleType::Import || *t == CssRuleType::Namespace
+        });
+        if !ok {
+            return Err(RulesMutateError::InvalidState);
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks whether `rule` may be inserted at `index` within `rules`,
+/// implementing the "insert a CSS rule" algorithm:
+///
+/// https://drafts.csswg.org/cssom/#insert-a-css-rule
+///
+/// This only covers the frozen-origin check (`InvalidState`) and the
+/// hierarchy checks (index bounds and rule ordering); syntax errors are
+/// reported by `CssRule::parse` itself before this is ever called.
+pub fn check_insert_rule_index(
+    origin: Origin,
+    rules: &[CssRule],
+    index: usize,
+    rule: &CssRule,
+) -> Result<(), RulesMutateError> {
+    if is_frozen(origin) {
+        return Err(RulesMutateError::InvalidState);
+    }
+
+    let existing_rule_types: Vec<CssRuleType> = rules.iter().map(|r| r.rule_type()).collect();
+    check_insert_index(&existing_rule_types, index, rule.rule_type())
+}
+
+/// Checks whether the rule at `index` may be removed from `rules`,
+/// implementing the "remove a CSS rule" algorithm:
+///
+/// https://drafts.csswg.org/cssom/#remove-a-css-rule
+pub fn check_remove_rule_index(
+    origin: Origin,
+    rules: &[CssRule],
+    index: usize,
+) -> Result<(), RulesMutateError> {
+    if is_frozen(origin) {
+        return Err(RulesMutateError::InvalidState);
+    }
+
+    let existing_rule_types: Vec<CssRuleType> = rules.iter().map(|r| r.rule_type()).collect();
+    check_remove_index(&existing_rule_types, index)
+}
+
+/// Parses `css` as a single rule and validates that it may be inserted at
+/// `index` within `rules`, implementing the "insert a CSS rule" algorithm
+/// modulo the caller updating the rule list itself:
+///
+/// https://drafts.csswg.org/cssom/#insert-a-css-rule
+///
+/// `css` is always parsed in the `State::Body` state, as for a nested
+/// rule, so an `@import` or `@namespace` rule is rejected by the parser
+/// with `HierarchyRequest` whatever `index` is, also at the top level of a
+/// stylesheet where that position would be valid.
+///
+/// On success, returns the new rule, deep-cloned so that it doesn't share
+/// state (e.g. an imported stylesheet) with whatever produced `css`.
+///
+/// Like `CssRule::parse`, this always wraps the new rule with
+/// `parent_stylesheet_contents.shared_lock` rather than taking a lock
+/// parameter of its own, so a rule inserted this way is guarded by the
+/// same lock `guard` (obtained from that same field) already proves is
+/// held.
+pub fn parse_insert_rule(
+    css: &str,
+    rules: &[CssRule],
+    index: usize,
+    parent_stylesheet_contents: &StylesheetContents,
+    guard: &SharedRwLockReadGuard,
+    loader: Option<&StylesheetLoader>,
+) -> Result<CssRule, RulesMutateError> {
+    if is_frozen(parent_stylesheet_contents.origin) {
+        return Err(RulesMutateError::InvalidState);
+    }
+
+    if index > rules.len() {
+        return Err(RulesMutateError::IndexSize);
+    }
+
+    let (new_rule, _) =
+        CssRule::parse(css, parent_stylesheet_contents, None, loader)?;
+
+    check_insert_rule_index(parent_stylesheet_contents.origin, rules, index, &new_rule)?;
+
+    let lock = &parent_stylesheet_contents.shared_lock;
+    Ok(new_rule.deep_clone_with_lock(lock, guard, &DeepCloneParams::default()))
+}
+
 impl DeepCloneWithLock for CssRule {
     /// Deep clones this CssRule.
+    ///
+    /// When `params.use_cow` is set, rules don't actually get a new,
+    /// independent `T` right away: each variant's `Arc` is shared (see
+    /// `LockedOrImmutable::share`) and the clone diverges into a real,
+    /// independent copy lazily, the next time it's mutated through the
+    /// CSSOM. Sharing a compound rule's `Arc` this way (`Media`,
+    /// `Supports`, `Document`, `Keyframes`) shares its entire sub-rule list
+    /// too, so the COW decision applies recursively for free. This makes
+    /// cloning a whole stylesheet cheap in the common case where most
+    /// rules are never subsequently modified.
     fn deep_clone_with_lock(
         &self,
         lock: &SharedRwLock,
         guard: &SharedRwLockReadGuard,
         params: &DeepCloneParams,
     ) -> CssRule {
+        if params.use_cow {
+            return match *self {
+                CssRule::Namespace(ref l) => CssRule::Namespace(l.share()),
+                CssRule::Import(ref l) => CssRule::Import(l.share()),
+                CssRule::Style(ref l) => CssRule::Style(l.share()),
+                CssRule::Media(ref l) => CssRule::Media(l.share()),
+                CssRule::FontFace(ref l) => CssRule::FontFace(l.share()),
+                CssRule::FontFeatureValues(ref l) => CssRule::FontFeatureValues(l.share()),
+                CssRule::CounterStyle(ref l) => CssRule::CounterStyle(l.share()),
+                CssRule::Viewport(ref l) => CssRule::Viewport(l.share()),
+                CssRule::Keyframes(ref l) => CssRule::Keyframes(l.share()),
+                CssRule::Supports(ref l) => CssRule::Supports(l.share()),
+                CssRule::Page(ref l) => CssRule::Page(l.share()),
+                CssRule::Document(ref l) => CssRule::Document(l.share()),
+            };
+        }
+
         match *self {
             CssRule::Namespace(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Namespace(Arc::new(lock.wrap(rule.clone())))
+                let rule = arc.read(guard);
+                CssRule::Namespace(arc.clone_wrapped_like(rule.clone(), lock))
             },
             CssRule::Import(ref arc) => {
-                let rule = arc.read_with(guard)
+                let rule = arc.read(guard)
                     .deep_clone_with_lock(lock, guard, params);
-                CssRule::Import(Arc::new(lock.wrap(rule)))
+                CssRule::Import(arc.clone_wrapped_like(rule, lock))
             },
             CssRule::Style(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Style(Arc::new(
-                    lock.wrap(rule.deep_clone_with_lock(lock, guard, params))))
+                let rule = arc.read(guard);
+                CssRule::Style(arc.clone_wrapped_like(
+                    rule.deep_clone_with_lock(lock, guard, params), lock))
             },
             CssRule::Media(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Media(Arc::new(
-                    lock.wrap(rule.deep_clone_with_lock(lock, guard, params))))
+                let rule = arc.read(guard);
+                CssRule::Media(arc.clone_wrapped_like(
+                    rule.deep_clone_with_lock(lock, guard, params), lock))
             },
             CssRule::FontFace(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::FontFace(Arc::new(lock.wrap(
-                    rule.clone_conditionally_gecko_or_servo())))
+                let rule = arc.read(guard);
+                CssRule::FontFace(arc.clone_wrapped_like(
+                    rule.clone_conditionally_gecko_or_servo(), lock))
             },
             CssRule::FontFeatureValues(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::FontFeatureValues(Arc::new(lock.wrap(rule.clone())))
+                let rule = arc.read(guard);
+                CssRule::FontFeatureValues(arc.clone_wrapped_like(rule.clone(), lock))
             },
             CssRule::CounterStyle(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::CounterStyle(Arc::new(lock.wrap(
-                    rule.clone_conditionally_gecko_or_servo())))
+                let rule = arc.read(guard);
+                CssRule::CounterStyle(arc.clone_wrapped_like(
+                    rule.clone_conditionally_gecko_or_servo(), lock))
             },
             CssRule::Viewport(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Viewport(Arc::new(lock.wrap(rule.clone())))
+                let rule = arc.read(guard);
+                CssRule::Viewport(arc.clone_wrapped_like(rule.clone(), lock))
             },
             CssRule::Keyframes(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Keyframes(Arc::new(
-                    lock.wrap(rule.deep_clone_with_lock(lock, guard, params))))
+                let rule = arc.read(guard);
+                CssRule::Keyframes(arc.clone_wrapped_like(
+                    rule.deep_clone_with_lock(lock, guard, params), lock))
             },
             CssRule::Supports(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Supports(Arc::new(
-                    lock.wrap(rule.deep_clone_with_lock(lock, guard, params))))
+                let rule = arc.read(guard);
+                CssRule::Supports(arc.clone_wrapped_like(
+                    rule.deep_clone_with_lock(lock, guard, params), lock))
             },
             CssRule::Page(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Page(Arc::new(
-                    lock.wrap(rule.deep_clone_with_lock(lock, guard, params))))
+                let rule = arc.read(guard);
+                CssRule::Page(arc.clone_wrapped_like(
+                    rule.deep_clone_with_lock(lock, guard, params), lock))
             },
             CssRule::Document(ref arc) => {
-                let rule = arc.read_with(guard);
-                CssRule::Document(Arc::new(
-                    lock.wrap(rule.deep_clone_with_lock(lock, guard, params))))
+                let rule = arc.read(guard);
+                CssRule::Document(arc.clone_wrapped_like(
+                    rule.deep_clone_with_lock(lock, guard, params), lock))
             },
         }
     }
 }
 
+impl CssRule {
+    /// Forks this rule into its own independent copy if it's currently
+    /// sharing its contents with another clone produced by a
+    /// copy-on-write `deep_clone_with_lock(.., &DeepCloneParams { use_cow:
+    /// true })` (see `LockedOrImmutable::share`) and hasn't diverged yet.
+    ///
+    /// CSSOM setters that mutate a rule in place (e.g. `CSSStyleRule`'s
+    /// `style` setter, `CSSMediaRule`'s rule-list mutators) must call this
+    /// first. Without it, two stylesheets produced by cheap-cloning one
+    /// another would keep aliasing the same `Arc<Locked<T>>`, so mutating
+    /// a rule through one stylesheet would be silently visible through
+    /// the other.
+    ///
+    /// Those setters themselves live in `components/script`, which isn't
+    /// part of this crate, so wiring them up to call this — and auditing
+    /// that every in-place CSSOM mutation does — isn't something this
+    /// commit can do; `StylesheetContents::clone_with_lock` is this
+    /// crate's only caller of `DeepCloneParams { use_cow: true }` so far.
+    pub fn make_unique(&mut self, lock: &SharedRwLock, guard: &SharedRwLockReadGuard) {
+        let is_shared = match *self {
+            CssRule::Namespace(ref l) => l.is_shared(),
+            CssRule::Import(ref l) => l.is_shared(),
+            CssRule::Style(ref l) => l.is_shared(),
+            CssRule::Media(ref l) => l.is_shared(),
+            CssRule::FontFace(ref l) => l.is_shared(),
+            CssRule::FontFeatureValues(ref l) => l.is_shared(),
+            CssRule::CounterStyle(ref l) => l.is_shared(),
+            CssRule::Viewport(ref l) => l.is_shared(),
+            CssRule::Keyframes(ref l) => l.is_shared(),
+            CssRule::Supports(ref l) => l.is_shared(),
+            CssRule::Page(ref l) => l.is_shared(),
+            CssRule::Document(ref l) => l.is_shared(),
+        };
+
+        if !is_shared {
+            return;
+        }
+
+        *self = self.deep_clone_with_lock(lock, guard, &DeepCloneParams::default());
+    }
+}
+
 impl ToCssWithGuard for CssRule {
     // https://drafts.csswg.org/cssom/#serialize-a-css-rule
     fn to_css<W>(&self, guard: &SharedRwLockReadGuard, dest: &mut W) -> fmt::Result
     where W: fmt::Write {
         match *self {
-            CssRule::Namespace(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Import(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Style(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::FontFace(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::FontFeatureValues(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::CounterStyle(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Viewport(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Keyframes(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Media(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Supports(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Page(ref lock) => lock.read_with(guard).to_css(guard, dest),
-            CssRule::Document(ref lock) => lock.read_with(guard).to_css(guard, dest),
+            CssRule::Namespace(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Import(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Style(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::FontFace(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::FontFeatureValues(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::CounterStyle(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Viewport(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Keyframes(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Media(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Supports(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Page(ref lock) => lock.read(guard).to_css(guard, dest),
+            CssRule::Document(ref lock) => lock.read(guard).to_css(guard, dest),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{check_insert_index, check_remove_index, CssRuleType, RulesMutateError};
+
+    #[test]
+    fn insert_import_after_non_import_is_rejected() {
+        let existing = [CssRuleType::Style];
+        assert_eq!(
+            check_insert_index(&existing, 1, CssRuleType::Import),
+            Err(RulesMutateError::HierarchyRequest)
+        );
+    }
+
+    #[test]
+    fn insert_import_before_other_imports_is_allowed() {
+        let existing = [CssRuleType::Import, CssRuleType::Import];
+        assert_eq!(check_insert_index(&existing, 1, CssRuleType::Import), Ok(()));
+    }
+
+    #[test]
+    fn insert_namespace_after_style_rule_is_rejected() {
+        let existing = [CssRuleType::Import, CssRuleType::Style];
+        assert_eq!(
+            check_insert_index(&existing, 2, CssRuleType::Namespace),
+            Err(RulesMutateError::HierarchyRequest)
+        );
+    }
+
+    #[test]
+    fn insert_namespace_after_imports_is_allowed() {
+        let existing = [CssRuleType::Import, CssRuleType::Namespace];
+        assert_eq!(check_insert_index(&existing, 2, CssRuleType::Namespace), Ok(()));
+    }
+
+    #[test]
+    fn insert_style_rule_before_import_is_rejected() {
+        let existing = [CssRuleType::Import];
+        assert_eq!(
+            check_insert_index(&existing, 0, CssRuleType::Style),
+            Err(RulesMutateError::HierarchyRequest)
+        );
+    }
+
+    #[test]
+    fn insert_style_rule_after_imports_and_namespaces_is_allowed() {
+        let existing = [CssRuleType::Import, CssRuleType::Namespace, CssRuleType::Style];
+        assert_eq!(check_insert_index(&existing, 3, CssRuleType::Style), Ok(()));
+    }
+
+    #[test]
+    fn insert_at_len_is_allowed() {
+        let existing = [CssRuleType::Style, CssRuleType::Style];
+        assert_eq!(check_insert_index(&existing, 2, CssRuleType::Style), Ok(()));
+    }
+
+    #[test]
+    fn insert_past_len_is_index_size_error() {
+        let existing = [CssRuleType::Style];
+        assert_eq!(
+            check_insert_index(&existing, 2, CssRuleType::Style),
+            Err(RulesMutateError::IndexSize)
+        );
+    }
+
+    #[test]
+    fn remove_namespace_with_only_imports_and_namespaces_is_allowed() {
+        let existing = [CssRuleType::Import, CssRuleType::Namespace];
+        assert_eq!(check_remove_index(&existing, 1), Ok(()));
+    }
+
+    #[test]
+    fn remove_namespace_with_other_content_present_is_invalid_state() {
+        let existing = [CssRuleType::Namespace, CssRuleType::Style];
+        assert_eq!(
+            check_remove_index(&existing, 0),
+            Err(RulesMutateError::InvalidState)
+        );
+    }
+
+    #[test]
+    fn remove_non_namespace_rule_is_allowed() {
+        let existing = [CssRuleType::Namespace, CssRuleType::Style];
+        assert_eq!(check_remove_index(&existing, 1), Ok(()));
+    }
+
+    #[test]
+    fn remove_out_of_range_index_is_index_size_error() {
+        let existing = [CssRuleType::Style];
+        assert_eq!(check_remove_index(&existing, 1), Err(RulesMutateError::IndexSize));
+    }
+}
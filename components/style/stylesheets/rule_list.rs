@@ -0,0 +1,111 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A list of CSS rules, as used by a stylesheet or a compound rule (one
+//! with its own nested block, like `@media` or `@supports`).
+
+use servo_arc::Arc;
+use shared_lock::Locked;
+use std::ops::Deref;
+use super::{
+    check_remove_rule_index, parse_insert_rule, CssRule, RulesMutateError, StylesheetContents,
+    StylesheetLoader,
+};
+
+/// A list of CSS rules, as found in a stylesheet or a compound rule's
+/// block.
+pub struct CssRules(pub Vec<CssRule>);
+
+impl Deref for CssRules {
+    type Target = [CssRule];
+
+    fn deref(&self) -> &[CssRule] {
+        &self.0
+    }
+}
+
+impl CssRules {
+    /// Creates a new, empty rule list.
+    pub fn new() -> Self {
+        CssRules(Vec::new())
+    }
+}
+
+/// Extra methods on `Arc<Locked<CssRules>>` that implement the actual
+/// `insertRule`/`deleteRule` CSSOM entry points in terms of the
+/// hierarchy/index validation in the parent module.
+///
+/// `insert_rule` validates through `parse_insert_rule` (which calls
+/// `check_insert_rule_index`) and `remove_rule` through
+/// `check_remove_rule_index`. Those free functions delegate the ordering
+/// checks to `check_insert_index` and `check_remove_index`, which work on
+/// plain `CssRuleType` slices and are unit-tested without needing a real
+/// `CssRules` or lock.
+///
+/// Both methods take `parent_stylesheet_contents` rather than a lock of
+/// their own: `parent_stylesheet_contents.shared_lock` is always the
+/// correct lock for `self` (see `StylesheetContents::shared_lock`), so
+/// there's no separate parameter for a caller to accidentally mismatch.
+pub trait CssRulesHelpers {
+    /// Implements the `insertRule` algorithm, parsing `rule` and inserting
+    /// it at `index`.
+    ///
+    /// https://drafts.csswg.org/cssom/#dom-cssstylesheet-insertrule
+    fn insert_rule(
+        &self,
+        rule: &str,
+        parent_stylesheet_contents: &StylesheetContents,
+        index: usize,
+        loader: Option<&StylesheetLoader>,
+    ) -> Result<CssRule, RulesMutateError>;
+
+    /// Implements the `deleteRule` algorithm, removing the rule at
+    /// `index`.
+    ///
+    /// https://drafts.csswg.org/cssom/#dom-cssstylesheet-deleterule
+    fn remove_rule(&self, parent_stylesheet_contents: &StylesheetContents, index: usize)
+        -> Result<(), RulesMutateError>;
+}
+
+impl CssRulesHelpers for Arc<Locked<CssRules>> {
+    fn insert_rule(
+        &self,
+        rule: &str,
+        parent_stylesheet_contents: &StylesheetContents,
+        index: usize,
+        loader: Option<&StylesheetLoader>,
+    ) -> Result<CssRule, RulesMutateError> {
+        let lock = &parent_stylesheet_contents.shared_lock;
+
+        // `parse_insert_rule` already validates the hierarchy (via
+        // `check_insert_rule_index`) before returning, so a read guard is
+        // all this half needs; the write guard is acquired separately,
+        // below, once there's an actual rule to insert.
+        let new_rule = {
+            let guard = lock.read();
+            let rules = self.read_with(&guard);
+            parse_insert_rule(rule, &rules.0, index, parent_stylesheet_contents, &guard, loader)?
+        };
+
+        let mut guard = lock.write();
+        self.write_with(&mut guard).0.insert(index, new_rule.clone());
+        Ok(new_rule)
+    }
+
+    fn remove_rule(&self, parent_stylesheet_contents: &StylesheetContents, index: usize)
+        -> Result<(), RulesMutateError>
+    {
+        let lock = &parent_stylesheet_contents.shared_lock;
+
+        {
+            let guard = lock.read();
+            let rules = self.read_with(&guard);
+            check_remove_rule_index(parent_stylesheet_contents.origin, &rules.0, index)?;
+        }
+
+        let mut guard = lock.write();
+        self.write_with(&mut guard).0.remove(index);
+        Ok(())
+    }
+}
@@ -0,0 +1,305 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Parsing of the top level of a stylesheet into `CssRule`s.
+
+use cssparser::{AtRuleParser, AtRuleType, CowRcStr, Parser, QualifiedRuleParser, SourceLocation};
+use cssparser::ParseError as CssParseError;
+use parser::{ParserContext, ParserErrorContext};
+use selector_parser::SelectorImpl;
+use selectors::parser::SelectorList;
+use shared_lock::SharedRwLock;
+use super::{
+    CounterStyleRule, CssRule, DocumentRule, FontFaceRule, FontFeatureValuesRule, ImportRule,
+    KeyframesRule, LockedOrImmutable, MediaRule, NamespaceRule, Namespaces, Origin, PageRule,
+    StyleRule, StylesheetLoader, SupportsRule, ViewportRule,
+};
+
+/// The parser error kind produced while parsing top-level rules.
+pub type RuleParseError<'i> = CssParseError<'i, ()>;
+
+/// The state in which a stylesheet parser can be, used to enforce the
+/// ordering rules of https://drafts.csswg.org/cssom/#insert-a-css-rule
+/// while parsing a whole stylesheet from scratch. A single rule parsed for
+/// insertion (`parse_insert_rule`) starts in `State::Body`, and its
+/// position is then checked by `check_insert_rule_index`.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum State {
+    /// The parser hasn't seen anything that rules out a leading `@import`.
+    Start = 1,
+    /// The parser has seen `@import` rules, but no other rule yet.
+    Imports = 2,
+    /// The parser has seen `@namespace` rules, but no other rule yet.
+    Namespaces = 3,
+    /// The parser has seen a rule that isn't `@import` or `@namespace`.
+    Body = 4,
+}
+
+/// The `AtRuleParser::PreludeNoBlock` and `AtRuleParser::PreludeBlock` types
+/// shared by `TopLevelRuleParser`. Each variant holds the preamble parsed
+/// from the at-rule's prelude, before its (optional) block is parsed.
+pub enum AtRulePrelude {
+    /// A `@font-face` rule prelude.
+    FontFace,
+    /// A `@font-feature-values` rule prelude.
+    FontFeatureValues,
+    /// A `@counter-style` prelude, with its name.
+    CounterStyle(String),
+    /// A `@media` prelude, with its raw, unparsed medium text.
+    Media(String),
+    /// A `@supports` prelude, with its raw, unparsed condition text.
+    Supports(String),
+    /// A `@viewport` rule prelude.
+    Viewport,
+    /// A `@keyframes` prelude, with the animation name.
+    Keyframes(String),
+    /// A `@page` rule prelude.
+    Page,
+    /// A `@document` prelude, with its raw, unparsed condition text.
+    Document(String),
+    /// An `@import` rule, fully parsed: it has no block, so by the time
+    /// `parse_prelude` returns, the rule itself is already built.
+    Import(ImportRule),
+    /// An `@namespace` rule, fully parsed: it has no block either.
+    Namespace(NamespaceRule),
+}
+
+/// The top-level parser, used for both whole stylesheets and rules parsed
+/// individually through `CssRule::parse`.
+///
+/// This builds every `CssRule` variant it produces through
+/// `LockedOrImmutable::new`, so that UA/user rules come out as the
+/// lock-free `Immutable` representation and author rules come out
+/// `Locked` behind `self.shared_lock` — see `LockedOrImmutable` in the
+/// parent module for why that distinction matters.
+pub struct TopLevelRuleParser<'a> {
+    /// The origin of the stylesheet being parsed.
+    pub stylesheet_origin: Origin,
+    /// The parser context for the rules we're parsing.
+    pub context: ParserContext<'a>,
+    /// The error-reporting context used while parsing.
+    pub error_context: ParserErrorContext<'a>,
+    /// The lock used to wrap author rules (already resolved, via
+    /// `rule_lock_for_origin`, to the process-wide UA/user lock when
+    /// `stylesheet_origin` isn't `Author`).
+    pub shared_lock: &'a SharedRwLock,
+    /// The loader used for `@import` rules, if any.
+    pub loader: Option<&'a StylesheetLoader>,
+    /// The current state of the parser.
+    pub state: State,
+    /// Whether we've seen a rule that violates the ordering rules of
+    /// https://drafts.csswg.org/cssom/#insert-a-css-rule, for error
+    /// reporting purposes.
+    pub had_hierarchy_error: bool,
+    /// The namespace prefixes declared by `@namespace` rules seen so far.
+    pub namespaces: &'a mut Namespaces,
+}
+
+impl<'a> TopLevelRuleParser<'a> {
+    fn wrap<T>(&self, value: T) -> LockedOrImmutable<T> {
+        LockedOrImmutable::new(value, self.stylesheet_origin, self.shared_lock)
+    }
+
+    /// Returns whether a hierarchy error was seen, and resets the flag.
+    pub fn take_had_hierarchy_error(&mut self) -> bool {
+        let had_hierarchy_error = self.had_hierarchy_error;
+        self.had_hierarchy_error = false;
+        had_hierarchy_error
+    }
+
+    fn check_state(&mut self, new_state: State) -> bool {
+        if self.state > new_state {
+            self.had_hierarchy_error = true;
+            return false;
+        }
+        self.state = new_state;
+        true
+    }
+}
+
+impl<'a, 'i> QualifiedRuleParser<'i> for TopLevelRuleParser<'a> {
+    type Prelude = SelectorList<SelectorImpl>;
+    type QualifiedRule = CssRule;
+    type Error = ();
+
+    fn parse_prelude<'t>(
+        &mut self,
+        input: &mut Parser<'i, 't>,
+    ) -> Result<Self::Prelude, RuleParseError<'i>> {
+        let selectors = SelectorList::parse(&self.context, input)?;
+        self.state = State::Body;
+        Ok(selectors)
+    }
+
+    fn parse_block<'t>(
+        &mut self,
+        selectors: Self::Prelude,
+        _location: SourceLocation,
+        input: &mut Parser<'i, 't>,
+    ) -> Result<Self::QualifiedRule, RuleParseError<'i>> {
+        let declarations = StyleRule::parse_declaration_block(
+            &self.context, &self.error_context, input,
+        );
+        Ok(CssRule::Style(self.wrap(StyleRule {
+            selectors: selectors,
+            block: declarations,
+            source_location: _location,
+        })))
+    }
+}
+
+impl<'a, 'i> AtRuleParser<'i> for TopLevelRuleParser<'a> {
+    type PreludeNoBlock = AtRulePrelude;
+    type PreludeBlock = AtRulePrelude;
+    type AtRule = CssRule;
+    type Error = ();
+
+    fn parse_prelude<'t>(
+        &mut self,
+        name: CowRcStr<'i>,
+        input: &mut Parser<'i, 't>,
+    ) -> Result<AtRuleType<Self::PreludeNoBlock, Self::PreludeBlock>, RuleParseError<'i>> {
+        match &*name {
+            "import" => {
+                if !self.check_state(State::Imports) {
+                    return Err(input.new_custom_error(()));
+                }
+                let (url, supports) = ImportRule::parse_prelude(&self.context, input)?;
+                let stylesheet = self.loader
+                    .expect("Expected a stylesheet loader for @import")
+                    .request_stylesheet(url, supports, self.shared_lock);
+                Ok(AtRuleType::WithoutBlock(AtRulePrelude::Import(stylesheet)))
+            },
+            "namespace" => {
+                if !self.check_state(State::Namespaces) {
+                    return Err(input.new_custom_error(()));
+                }
+                let (prefix, url) = NamespaceRule::parse_prelude(input)?;
+                if let Some(ref prefix) = prefix {
+                    self.namespaces.prefixes.insert(prefix.clone(), url.clone());
+                }
+                Ok(AtRuleType::WithoutBlock(AtRulePrelude::Namespace(
+                    NamespaceRule { prefix: prefix, url: url },
+                )))
+            },
+            "font-face" => {
+                self.check_state(State::Body);
+                Ok(AtRuleType::WithBlock(AtRulePrelude::FontFace))
+            },
+            "font-feature-values" => {
+                self.check_state(State::Body);
+                Ok(AtRuleType::WithBlock(AtRulePrelude::FontFeatureValues))
+            },
+            "counter-style" => {
+                self.check_state(State::Body);
+                let name = CounterStyleRule::parse_name(input)?;
+                Ok(AtRuleType::WithBlock(AtRulePrelude::CounterStyle(name)))
+            },
+            "viewport" => {
+                self.check_state(State::Body);
+                Ok(AtRuleType::WithBlock(AtRulePrelude::Viewport))
+            },
+            "keyframes" => {
+                self.check_state(State::Body);
+                let name = KeyframesRule::parse_name(input)?;
+                Ok(AtRuleType::WithBlock(AtRulePrelude::Keyframes(name)))
+            },
+            "page" => {
+                self.check_state(State::Body);
+                Ok(AtRuleType::WithBlock(AtRulePrelude::Page))
+            },
+            "media" => {
+                self.check_state(State::Body);
+                let medium = input.parse_until_before_block_as_text();
+                Ok(AtRuleType::WithBlock(AtRulePrelude::Media(medium)))
+            },
+            "supports" => {
+                self.check_state(State::Body);
+                let condition = input.parse_until_before_block_as_text();
+                Ok(AtRuleType::WithBlock(AtRulePrelude::Supports(condition)))
+            },
+            "document" => {
+                self.check_state(State::Body);
+                let condition = input.parse_until_before_block_as_text();
+                Ok(AtRuleType::WithBlock(AtRulePrelude::Document(condition)))
+            },
+            _ => Err(input.new_custom_error(())),
+        }
+    }
+
+    fn rule_without_block(&mut self, prelude: Self::PreludeNoBlock) -> Self::AtRule {
+        match prelude {
+            AtRulePrelude::Import(rule) => CssRule::Import(self.wrap(rule)),
+            AtRulePrelude::Namespace(rule) => CssRule::Namespace(self.wrap(rule)),
+            _ => unreachable!("only @import and @namespace preludes have no block"),
+        }
+    }
+
+    fn parse_block<'t>(
+        &mut self,
+        prelude: Self::PreludeBlock,
+        _location: SourceLocation,
+        input: &mut Parser<'i, 't>,
+    ) -> Result<Self::AtRule, RuleParseError<'i>> {
+        Ok(match prelude {
+            AtRulePrelude::FontFace => {
+                CssRule::FontFace(self.wrap(FontFaceRule::parse(&self.context, input)?))
+            },
+            AtRulePrelude::FontFeatureValues => {
+                CssRule::FontFeatureValues(self.wrap(
+                    FontFeatureValuesRule::parse(&self.context, input)?,
+                ))
+            },
+            AtRulePrelude::CounterStyle(name) => {
+                CssRule::CounterStyle(self.wrap(
+                    CounterStyleRule::parse_body(name, &self.context, input)?,
+                ))
+            },
+            AtRulePrelude::Viewport => {
+                CssRule::Viewport(self.wrap(ViewportRule::parse(&self.context, input)?))
+            },
+            AtRulePrelude::Keyframes(name) => {
+                CssRule::Keyframes(self.wrap(
+                    KeyframesRule::parse_body(name, &self.context, self.shared_lock, input)?,
+                ))
+            },
+            AtRulePrelude::Page => {
+                CssRule::Page(self.wrap(PageRule::parse(&self.context, input)?))
+            },
+            AtRulePrelude::Media(medium) => {
+                let rules = parse_nested_rules(self, input);
+                CssRule::Media(self.wrap(MediaRule::new(medium, rules, self.shared_lock)))
+            },
+            AtRulePrelude::Supports(condition) => {
+                let rules = parse_nested_rules(self, input);
+                CssRule::Supports(self.wrap(SupportsRule::new(condition, rules, self.shared_lock)))
+            },
+            AtRulePrelude::Document(condition) => {
+                let rules = parse_nested_rules(self, input);
+                CssRule::Document(self.wrap(DocumentRule::new(condition, rules, self.shared_lock)))
+            },
+        })
+    }
+}
+
+/// Parses the rules nested inside a compound at-rule's block (`@media`,
+/// `@supports`, `@document`), reusing `self`'s lock and origin so that the
+/// nested rules end up wrapped exactly like their top-level siblings.
+fn parse_nested_rules(parser: &mut TopLevelRuleParser, input: &mut Parser) -> Vec<CssRule> {
+    let mut nested = TopLevelRuleParser {
+        stylesheet_origin: parser.stylesheet_origin,
+        context: parser.context.clone(),
+        error_context: parser.error_context.clone(),
+        shared_lock: parser.shared_lock,
+        loader: parser.loader,
+        state: State::Body,
+        had_hierarchy_error: false,
+        namespaces: parser.namespaces,
+    };
+    ::cssparser::parse_nested_block(input, |input| {
+        ::cssparser::RuleListParser::new_for_nested_rule(input, &mut nested)
+            .filter_map(Result::ok)
+            .collect()
+    })
+}
@@ -0,0 +1,198 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A stylesheet and the data it's parsed into.
+
+use cssparser::{Parser, ParserInput, RuleListParser};
+use error_reporting::NullReporter;
+use parser::{ParserContext, ParserErrorContext};
+use parking_lot::RwLock;
+use servo_arc::Arc;
+use shared_lock::{DeepCloneParams, DeepCloneWithLock, Locked, SharedRwLock};
+use std::collections::HashMap;
+use style_traits::PARSING_MODE_DEFAULT;
+use super::{
+    rule_lock_for_origin, CssRule, CssRules, Origin, State, StylesheetLoader, TopLevelRuleParser,
+    UrlExtraData,
+};
+
+/// A namespace prefix, as declared via `@namespace url as prefix;`.
+pub type Prefix = String;
+/// A namespace URL.
+pub type Namespace = String;
+
+/// The quirks mode of the document a stylesheet belongs to, which affects
+/// some of how it's parsed (e.g. case-insensitive HTML attribute
+/// selectors).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QuirksMode {
+    /// No quirks mode.
+    NoQuirks,
+    /// Limited quirks mode.
+    LimitedQuirks,
+    /// Full quirks mode.
+    Quirks,
+}
+
+/// The namespace prefixes declared by a stylesheet's `@namespace` rules,
+/// consulted while parsing any selectors in the same stylesheet that use
+/// a namespace prefix.
+#[derive(Clone, Default)]
+pub struct Namespaces {
+    /// The default (unprefixed) namespace, if one was declared.
+    pub default: Option<Namespace>,
+    /// Prefix -> namespace URL mappings declared via `@namespace url as
+    /// prefix;`.
+    pub prefixes: HashMap<Prefix, Namespace>,
+}
+
+/// The parsed contents of a stylesheet: its rules, plus the data needed to
+/// parse more of them later (e.g. via CSSOM's `insertRule`).
+pub struct StylesheetContents {
+    /// The list of rules, guarded by `shared_lock`.
+    pub rules: Arc<Locked<CssRules>>,
+    /// This stylesheet's origin.
+    pub origin: Origin,
+    /// The lock that guards `rules`, and — since parsing threads it
+    /// through recursively — every nested `Arc<Locked<_>>` reachable from
+    /// them (the child rule lists of `@media`/`@supports`/`@document`
+    /// rules, and the rules themselves when `origin` is `Author`).
+    ///
+    /// Resolved once, at construction time, via `rule_lock_for_origin`:
+    /// for a UA/user stylesheet this is always the process-wide
+    /// `UA_OR_USER_RULES_LOCK`, never whatever lock the owning document
+    /// happens to use. Anything that needs to read or mutate this
+    /// stylesheet's rules later (`to_css`, selector matching,
+    /// `CssRulesHelpers::insert_rule`/`remove_rule`) must go through this
+    /// field rather than an independently-obtained lock — using the
+    /// wrong one trips the "guard from an unrelated SharedRwLock" assert
+    /// in `Locked::read_with`/`write_with`.
+    pub shared_lock: SharedRwLock,
+    /// Extra data needed to resolve url values in this stylesheet.
+    pub url_data: RwLock<UrlExtraData>,
+    /// The quirks mode of the document this stylesheet belongs to.
+    pub quirks_mode: QuirksMode,
+    /// The namespace prefixes declared by this stylesheet's `@namespace`
+    /// rules.
+    pub namespaces: RwLock<Namespaces>,
+}
+
+impl StylesheetContents {
+    /// Parses a new stylesheet from `css`, returning its contents.
+    ///
+    /// `document_lock` is only actually used for `Author`-origin
+    /// stylesheets; see `shared_lock`.
+    pub fn from_str(
+        css: &str,
+        url_data: UrlExtraData,
+        origin: Origin,
+        document_lock: &SharedRwLock,
+        quirks_mode: QuirksMode,
+        loader: Option<&StylesheetLoader>,
+    ) -> Self {
+        let shared_lock = rule_lock_for_origin(origin, document_lock).clone();
+        let url_data = RwLock::new(url_data);
+        let mut namespaces = Namespaces::default();
+
+        let rules = {
+            let error_reporter = NullReporter;
+            let url_data = url_data.read();
+            let context = ParserContext::new(
+                origin, &url_data, None, PARSING_MODE_DEFAULT, quirks_mode,
+            );
+            let mut input = ParserInput::new(css);
+            let mut input = Parser::new(&mut input);
+            let mut rule_parser = TopLevelRuleParser {
+                stylesheet_origin: origin,
+                context: context,
+                error_context: ParserErrorContext { error_reporter: &error_reporter },
+                shared_lock: &shared_lock,
+                loader: loader,
+                state: State::Start,
+                had_hierarchy_error: false,
+                namespaces: &mut namespaces,
+            };
+            RuleListParser::new_for_stylesheet(&mut input, &mut rule_parser)
+                .filter_map(Result::ok)
+                .collect::<Vec<CssRule>>()
+        };
+
+        StylesheetContents {
+            rules: Arc::new(shared_lock.wrap(CssRules(rules))),
+            origin: origin,
+            shared_lock: shared_lock,
+            url_data: url_data,
+            quirks_mode: quirks_mode,
+            namespaces: RwLock::new(namespaces),
+        }
+    }
+
+    /// Performs a cheap, copy-on-write deep clone of this stylesheet's
+    /// contents: rules that haven't been mutated through the CSSOM since
+    /// they were parsed are shared with the clone (see
+    /// `LockedOrImmutable::share`) rather than eagerly recreated, and
+    /// only fork into independent copies later, the first time a CSSOM
+    /// mutation reaches one of them (see `CssRule::make_unique`). This is
+    /// what makes cloning a whole stylesheet — e.g. for `Node::clone_node`
+    /// on a `<style>` element — cheap in the common case where most
+    /// rules are never subsequently edited.
+    ///
+    /// `new_document_lock` is the lock the clone's `Author`-origin rules
+    /// (if any) should use; as with `from_str`, UA/user rules ignore it
+    /// and keep sharing `UA_OR_USER_RULES_LOCK`.
+    pub fn clone_with_lock(&self, new_document_lock: &SharedRwLock) -> Self {
+        let shared_lock = rule_lock_for_origin(self.origin, new_document_lock).clone();
+        let guard = self.shared_lock.read();
+        let rules = self.rules.read_with(&guard);
+        let params = DeepCloneParams { use_cow: true };
+        let cloned_rules: Vec<CssRule> = rules.0.iter()
+            .map(|rule| rule.deep_clone_with_lock(&shared_lock, &guard, &params))
+            .collect();
+
+        StylesheetContents {
+            rules: Arc::new(shared_lock.wrap(CssRules(cloned_rules))),
+            origin: self.origin,
+            shared_lock: shared_lock,
+            url_data: RwLock::new(self.url_data.read().clone()),
+            quirks_mode: self.quirks_mode,
+            namespaces: RwLock::new(self.namespaces.read().clone()),
+        }
+    }
+}
+
+/// A CSS stylesheet as exposed to the rest of the engine: its contents,
+/// plus whether the document has disabled it.
+pub struct Stylesheet {
+    /// The parsed contents of this stylesheet.
+    pub contents: StylesheetContents,
+    /// Whether this stylesheet is currently disabled via CSSOM.
+    pub disabled: ::std::sync::atomic::AtomicBool,
+}
+
+/// A trait for getting the `StylesheetContents` of a type that represents
+/// a stylesheet within a document (e.g. wrapping it with DOM-specific
+/// bookkeeping), so that code that only cares about the rules doesn't need
+/// to know about that bookkeeping.
+pub trait StylesheetInDocument {
+    /// Returns this stylesheet's contents.
+    fn contents(&self) -> &StylesheetContents;
+}
+
+impl StylesheetInDocument for Stylesheet {
+    fn contents(&self) -> &StylesheetContents {
+        &self.contents
+    }
+}
+
+/// A style sheet bound to a document. It holds only the shared
+/// `Stylesheet`, not the node in the document it came from.
+pub struct DocumentStyleSheet(pub Arc<Stylesheet>);
+
+/// The set of user-agent stylesheets that apply to every document,
+/// parsed once at startup and shared (lock-free, via
+/// `LockedOrImmutable::Immutable`) across every document afterwards.
+pub struct UserAgentStylesheets {
+    /// The user-agent stylesheets themselves.
+    pub user_or_user_agent_stylesheets: Vec<Stylesheet>,
+}